@@ -4,7 +4,7 @@
 /// and adds synonyms to codes where the PQCMC preferred term differs.
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::fs::File;
 use std::io::{BufReader, BufWriter,Result};
@@ -12,6 +12,9 @@ use std::io::{BufReader, BufWriter,Result};
 const PATH: &str = "./packages/fhir.tx.support.r4/package/CodeSystem-nciThesaurus-fragment.json";
 const OUT_PATH: &str = "output.json";
 const DEFAULT_INPUT_PATH: &str = "new-codes.json";
+// the schema/version number this build of the tool knows how to produce;
+// `CodeSystem::run_migrations` brings an older input file up to this
+const TARGET_SCHEMA_VERSION: u32 = 2;
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 /// only (de)serializes the properties of the original codesystem
@@ -29,11 +32,141 @@ struct CodeSystem {
     copyright: String,
     case_sensitive: bool,
     content: String,
+    // doubles as the migration subsystem's schema/version marker; absent on
+    // an input file that's never been through `run_migrations`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
     concept: Vec<Concept>,
 }
 
 struct Settings {
-  suppress_info_level: bool
+  suppress_info_level: bool,
+  synonym_overrides: Setting<HashMap<String, Vec<String>>>,
+  // when true, newly-added codes get every remaining thesaurus synonym and
+  // a missing definition filled in, instead of just the preferred term
+  full_thesaurus_enrichment: bool,
+  // when true, the output CodeSystem nests concepts under their thesaurus
+  // parents instead of staying a flat list
+  build_hierarchy: bool,
+  // allow-list of `concept_in_subset` names; None means don't filter on subset
+  subset_filter: Option<Vec<String>>,
+  // allow-list of `semantic_type` values; None means don't filter on semantic type
+  semantic_type_filter: Option<Vec<String>>,
+  // where to write the full diagnostics list as JSON; None means console-only
+  diagnostics_report_path: Option<String>,
+}
+
+impl Settings {
+  /// a new code is only promoted when its thesaurus row passes every filter
+  /// that's configured; an unconfigured filter doesn't constrain anything
+  fn passes_filters(&self, row: &ThesaurusRow) -> bool {
+    let subset_match = self.subset_filter.as_ref()
+      .map(|allowed| row.concept_in_subset.iter().any(|s| allowed.contains(s)));
+    let semantic_type_match = self.semantic_type_filter.as_ref()
+      .map(|allowed| allowed.contains(&row.semantic_type));
+    match (subset_match, semantic_type_match) {
+      (None, None) => true,
+      (Some(subset_match), None) => subset_match,
+      (None, Some(semantic_type_match)) => semantic_type_match,
+      (Some(subset_match), Some(semantic_type_match)) => subset_match && semantic_type_match,
+    }
+  }
+}
+
+/// a tri-state setting, mirroring MeiliSearch's `Setting<T>`: "leave alone"
+/// (`NotSet`, absent), "explicitly clear" (`Reset`, JSON `null`), or "apply
+/// this value" (`Set`, any other JSON value)
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(untagged)]
+enum Setting<T> {
+    Set(T),
+    Reset,
+    #[default]
+    NotSet,
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Setting<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Deserialize::deserialize(deserializer).map(|x: Option<T>| match x {
+            Some(x) => Setting::Set(x),
+            None => Setting::Reset,
+        })
+    }
+}
+
+/// loads a curator-maintained synonym dictionary from `path`, if given. a
+/// missing path is `NotSet`; a file containing JSON `null` is `Reset`;
+/// anything else is parsed as the code -> extra-synonyms map (`Set`)
+fn synonym_setting_from_file(path: Option<String>) -> Result<Setting<HashMap<String, Vec<String>>>> {
+  match path {
+    None => Ok(Setting::NotSet),
+    Some(path) => {
+      let file = File::open(path)?;
+      let reader = BufReader::new(file);
+      let setting = serde_json::from_reader(reader)?;
+      Ok(setting)
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+  Info,
+  Warn,
+  Error,
+}
+
+impl Display for Severity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", match self {
+      Severity::Info => "INFO",
+      Severity::Warn => "WARN",
+      Severity::Error => "ERR",
+    })
+  }
+}
+
+/// a machine-readable diagnostic emitted while merging codes; `code` is a
+/// stable identifier (e.g. `"mismatched-display"`) a pipeline can match on
+#[derive(Debug, Clone, Serialize)]
+struct Diagnostic {
+  severity: Severity,
+  code: &'static str,
+  concept_code: String,
+  message: String,
+}
+
+impl Diagnostic {
+  fn new(severity: Severity, code: &'static str, concept_code: &str, message: String) -> Self {
+    Diagnostic { severity, code, concept_code: concept_code.to_string(), message }
+  }
+}
+
+impl Display for Diagnostic {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}:\t[{}]\tcode '{}': {}", self.severity, self.code, self.concept_code, self.message)
+  }
+}
+
+/// prints diagnostics (respecting `suppress_info_level`), then writes the
+/// full list to `diagnostics_report_path` as JSON, if configured
+fn report_diagnostics(diagnostics: &[Diagnostic], settings: &Settings) -> Result<()> {
+  for diagnostic in diagnostics {
+    if diagnostic.severity == Severity::Info && settings.suppress_info_level {
+      continue;
+    }
+    println!("{}", diagnostic);
+  }
+  if let Some(path) = &settings.diagnostics_report_path {
+    let file = File::create(path)?;
+    let writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(writer, diagnostics)?;
+  }
+  Ok(())
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,8 +189,8 @@ impl Display for Concept {
 
 impl Concept {
 
-  fn replace_display_with_new_term(self,new_display: String)->Self {
-    // if the PQCMC preferred term is already the NCIT preferred term, don't 
+  fn replace_display_with_new_term(self,new_display: String, diagnostics: &mut Vec<Diagnostic>)->Self {
+    // if the PQCMC preferred term is already the NCIT preferred term, don't
     // do anything
     if self.display.to_lowercase() == new_display.to_ascii_lowercase() {
       Concept {
@@ -69,11 +202,13 @@ impl Concept {
     } else {
       // copy out the pqcmc preferred term
       let pqcmc_preferred_term = self.display.clone();
-      println!("WARN:\tPQCMC term does not match NCIT preferred term:");
-      println!("\t\tCode: {}",self.code);
-      println!("\t\tPQCMC term: {}",pqcmc_preferred_term);
-      println!("\t\tNCIT term:  {}",new_display);
-      let mut new_concept = 
+      diagnostics.push(Diagnostic::new(
+        Severity::Warn,
+        "pqcmc-term-differs",
+        &self.code,
+        format!("PQCMC term '{}' does not match NCIT preferred term '{}'", pqcmc_preferred_term, new_display),
+      ));
+      let mut new_concept =
       Concept {
         display: new_display,
         // don't copy the definition
@@ -95,22 +230,103 @@ impl Concept {
   fn add_synonym(&mut self, synonym: String) {
     self.add_designation(Designation::synonym(synonym));
   }
+  fn has_designation_value(&self, value: &str) -> bool {
+    self.display.to_lowercase() == value.to_lowercase()
+      || self.designation.as_ref().unwrap_or(&Vec::new())
+        .iter().any(|d| d.value.to_lowercase() == value.to_lowercase())
+  }
+  /// removes every designation the curator's synonym dictionary previously
+  /// added, leaving NCIT/PQCMC-derived designations untouched
+  fn strip_curator_designations(&mut self) {
+    if let Some(designations) = self.designation.as_mut() {
+      designations.retain(|d| !d.curator_added);
+    }
+    if self.designation.as_ref().is_some_and(|d| d.is_empty()) {
+      self.designation = None;
+    }
+  }
+  /// applies the curator synonym dictionary's `Set`/`Reset`/`NotSet`
+  /// instruction for this concept's code; `Set` strips then re-applies so
+  /// re-runs stay idempotent
+  fn apply_synonym_overrides(&mut self, overrides: &Setting<HashMap<String, Vec<String>>>) {
+    match overrides {
+      Setting::NotSet => {}
+      Setting::Reset => self.strip_curator_designations(),
+      Setting::Set(map) => {
+        self.strip_curator_designations();
+        if let Some(synonyms) = map.get(&self.code) {
+          for synonym in synonyms {
+            if !self.has_designation_value(synonym) {
+              self.add_designation(Designation::curator_synonym(synonym.clone()));
+            }
+          }
+        }
+      }
+    }
+  }
+  /// adds every remaining thesaurus synonym as a designation and backfills
+  /// a missing definition, deduping case-insensitively
+  fn enrich_from_thesaurus(&mut self, row: &ThesaurusRow) {
+    for synonym in row.synonyms.iter().skip(1) {
+      if !self.has_designation_value(synonym) {
+        self.add_synonym(synonym.clone());
+      }
+    }
+    if self.definition.is_none() && !row.definition.is_empty() {
+      self.definition = Some(row.definition.clone());
+    }
+  }
+  /// flattens a concept tree of any nesting depth into one top-level list
+  fn flatten(concepts: Vec<Concept>) -> Vec<Concept> {
+    let mut flat = Vec::new();
+    for mut concept in concepts {
+      if let Some(children) = concept.concept.take() {
+        flat.extend(Concept::flatten(children));
+      }
+      flat.push(concept);
+    }
+    flat
+  }
+  /// runs `f` against every concept at any nesting depth
+  fn walk_mut(concepts: &mut [Concept], f: &mut impl FnMut(&mut Concept)) {
+    for concept in concepts.iter_mut() {
+      f(concept);
+      if let Some(children) = concept.concept.as_mut() {
+        Concept::walk_mut(children, f);
+      }
+    }
+  }
 }
 
 
+fn is_false(b: &bool) -> bool {
+  !b
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Designation {
     #[serde(rename = "use")]
     #[serde(skip_serializing_if = "Option::is_none")]
     _use: Option<Use>,
     value: String,
+    // not part of the base FHIR shape; tracks whether this designation came
+    // from the curator's synonym dictionary, so a later `Reset` knows what
+    // it's allowed to strip back out. omitted from output when false
+    #[serde(rename = "curatorAdded")]
+    #[serde(default, skip_serializing_if = "is_false")]
+    curator_added: bool,
 }
 
 impl Designation {
   fn synonym (synonym: String) -> Self{
     Designation { _use: Some(
       Use::synonym()
-    ), value: synonym }
+    ), value: synonym, curator_added: false }
+  }
+  fn curator_synonym (synonym: String) -> Self{
+    Designation { _use: Some(
+      Use::synonym()
+    ), value: synonym, curator_added: true }
   }
 }
 
@@ -137,6 +353,7 @@ struct Statistics {
     wrong_display: usize,
     not_ncit_code: usize,
     new_code: usize,
+    skipped_by_filter: usize,
 }
 
 impl Statistics {
@@ -146,6 +363,7 @@ impl Statistics {
             wrong_display: 0,
             new_code: 0,
             not_ncit_code: 0,
+            skipped_by_filter: 0,
         }
     }
 }
@@ -156,15 +374,132 @@ impl Display for Statistics {
         writeln!(f, "pre-existing codes:\t{}", self.already_exists)?;
         writeln!(f, "wrong displays:\t\t{}", self.wrong_display)?;
         writeln!(f, "non-NCIT codes:\t\t{}", self.not_ncit_code)?;
-        writeln!(f, "new codes:\t\t{}", self.new_code)
+        writeln!(f, "new codes:\t\t{}", self.new_code)?;
+        writeln!(f, "skipped by filter:\t{}", self.skipped_by_filter)
     }
 }
 
+/// a named, ordered step in the CodeSystem's schema evolution. `pre` runs
+/// before new codes are merged in, `post` runs after; only runs when
+/// `to_version` is greater than the CodeSystem's recorded `version`
+struct Migration {
+  name: &'static str,
+  to_version: u32,
+  pre: fn(&mut CodeSystem, &mut Vec<Diagnostic>),
+  post: fn(&mut CodeSystem, &mut Vec<Diagnostic>),
+}
+
+fn noop_migration_step(_system: &mut CodeSystem, _diagnostics: &mut Vec<Diagnostic>) {}
+
+/// gives every free-text designation (one with no `use` coded) a SNOMED
+/// synonym use code, so older files merged before designations carried a
+/// `use` are brought in line with what `Designation::synonym` produces today.
+/// walks the whole concept tree, not just the top level
+fn move_legacy_synonyms_into_coded_designations(system: &mut CodeSystem, diagnostics: &mut Vec<Diagnostic>) {
+  Concept::walk_mut(&mut system.concept, &mut |concept| {
+    if let Some(designations) = concept.designation.as_mut() {
+      for designation in designations.iter_mut() {
+        if designation._use.is_none() {
+          designation._use = Some(Use::synonym());
+          diagnostics.push(Diagnostic::new(
+            Severity::Info,
+            "migration-legacy-synonym-coded",
+            &concept.code,
+            format!("free-text designation '{}' given a SNOMED synonym use code", designation.value),
+          ));
+        }
+      }
+    }
+  });
+}
+
+/// capitalizes the first character of every concept's display, so concepts
+/// merged in before displays were required to be sentence-cased line up
+/// with the rest of the CodeSystem. walks the whole concept tree, not just
+/// the top level
+fn normalize_display_casing(system: &mut CodeSystem, diagnostics: &mut Vec<Diagnostic>) {
+  Concept::walk_mut(&mut system.concept, &mut |concept| {
+    let mut chars = concept.display.chars();
+    let normalized = match chars.next() {
+      Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+      None => concept.display.clone(),
+    };
+    if normalized != concept.display {
+      diagnostics.push(Diagnostic::new(
+        Severity::Info,
+        "migration-display-casing-normalized",
+        &concept.code,
+        format!("display '{}' normalized to '{}'", concept.display, normalized),
+      ));
+      concept.display = normalized;
+    }
+  });
+}
+
+const MIGRATIONS: &[Migration] = &[
+  Migration {
+    name: "move legacy free-text synonyms into proper SNOMED-coded designations",
+    to_version: 1,
+    pre: move_legacy_synonyms_into_coded_designations,
+    post: noop_migration_step,
+  },
+  Migration {
+    name: "normalize display casing",
+    to_version: 2,
+    pre: noop_migration_step,
+    post: normalize_display_casing,
+  },
+];
+
 impl CodeSystem {
-  /// looks for a code in the vector of concepts. returns an option
-  /// containing a borrowed, mutable concept if it finds one
+  /// runs the `pre` (or `post`, if `run_pre` is false) half of every
+  /// migration still pending against this CodeSystem's recorded `version`
+  fn run_migrations(&mut self, run_pre: bool, diagnostics: &mut Vec<Diagnostic>) {
+    let current_version: u32 = self.version.as_deref()
+      .and_then(|v| v.parse().ok())
+      .unwrap_or(0);
+    for migration in MIGRATIONS {
+      if migration.to_version <= current_version {
+        continue;
+      }
+      diagnostics.push(Diagnostic::new(
+        Severity::Info,
+        "migration-running",
+        "-",
+        format!("running migration '{}' ({})", migration.name, if run_pre { "pre" } else { "post" }),
+      ));
+      if run_pre {
+        (migration.pre)(self, diagnostics);
+      } else {
+        (migration.post)(self, diagnostics);
+      }
+    }
+  }
+  /// records that this CodeSystem is current with every migration this
+  /// build knows about. never moves the recorded version backwards
+  fn bump_schema_version(&mut self) {
+    let current_version: u32 = self.version.as_deref()
+      .and_then(|v| v.parse().ok())
+      .unwrap_or(0);
+    self.version = Some(current_version.max(TARGET_SCHEMA_VERSION).to_string());
+  }
+  /// looks for a code anywhere in the concept tree, including nested
+  /// children. returns an option containing a borrowed, mutable concept if
+  /// it finds one
   fn get_mutable_concept_by_code(&mut self, code: &String)->Option<&mut Concept> {
-    self.concept.iter_mut().find(|x| &x.code == code)
+    fn find<'a>(concepts: &'a mut [Concept], code: &String) -> Option<&'a mut Concept> {
+      for concept in concepts {
+        if &concept.code == code {
+          return Some(concept);
+        }
+        let found = concept.concept.as_mut().and_then(|children| find(children, code));
+        if found.is_some() {
+          return found;
+        }
+      }
+      None
+    }
+    find(&mut self.concept, code)
   }
   /// push a concept into the codesystem. consumes the concept
   fn add_concept(&mut self, concept: Concept) {
@@ -178,40 +513,63 @@ impl CodeSystem {
     concept: Concept,
     stats: &mut Statistics,
     settings: &Settings,
-    thesaurus: &Thesaurus
+    thesaurus: &Thesaurus,
+    diagnostics: &mut Vec<Diagnostic>,
   ) {
     if let Some(existing_concept) = self.get_mutable_concept_by_code(&concept.code) {
       stats.already_exists += 1;
       // if the codes are the same, but the display names are different, then
       // there is potentially something wrong. Don't care about case
-      // check to see if the term already exists as a synonym 
+      // check to see if the term already exists as a synonym
       let does_not_exist_as_synonym = existing_concept.designation
         .clone().unwrap_or(Vec::new())
         .iter().find(|f|f.value.to_lowercase() == concept.display.to_lowercase()).is_none();
       if existing_concept.display.to_lowercase() != concept.display.to_lowercase() && does_not_exist_as_synonym{
-        println!("WARN:\tMismatched displays for code '{}':", concept.code);
-        println!(
-            "\told: '{}'\r\n\tnew: '{}'",
-            existing_concept.display, concept.display
-        );
+        diagnostics.push(Diagnostic::new(
+          Severity::Warn,
+          "mismatched-display",
+          &concept.code,
+          format!("old: '{}', new: '{}'", existing_concept.display, concept.display),
+        ));
         existing_concept.add_synonym(concept.display);
         stats.wrong_display += 1;
       } else {
-        if !settings.suppress_info_level {
-          println!(
-            "INFO:\tcode '{}' already present with correct display",
-            concept.code
-          )
-        }
+        diagnostics.push(Diagnostic::new(
+          Severity::Info,
+          "code-already-present",
+          &concept.code,
+          "already present with correct display".to_string(),
+        ));
       }
+      existing_concept.apply_synonym_overrides(&settings.synonym_overrides);
     } else {
       // only add codes that can be found in the thesaurus
       if let Some(row) = thesaurus.get(&concept.code) {
-        self.add_concept(concept.replace_display_with_new_term(row.get_ncit_preferred_term()));
-        stats.new_code += 1;
+        if !settings.passes_filters(row) {
+          diagnostics.push(Diagnostic::new(
+            Severity::Info,
+            "skipped-by-filter",
+            &concept.code,
+            "excluded by subset/semantic-type filter".to_string(),
+          ));
+          stats.skipped_by_filter += 1;
+        } else {
+          let mut new_concept = concept.replace_display_with_new_term(row.get_ncit_preferred_term(), diagnostics);
+          if settings.full_thesaurus_enrichment {
+            new_concept.enrich_from_thesaurus(row);
+          }
+          new_concept.apply_synonym_overrides(&settings.synonym_overrides);
+          self.add_concept(new_concept);
+          stats.new_code += 1;
+        }
       }
       else {
-        println!("ERR:\tnon-NCIT code: {}", concept);
+        diagnostics.push(Diagnostic::new(
+          Severity::Error,
+          "non-ncit-code",
+          &concept.code,
+          format!("non-NCIT code: {}", concept),
+        ));
         stats.not_ncit_code += 1;
       }
 
@@ -231,6 +589,98 @@ impl CodeSystem {
     serde_json::to_writer_pretty(writer, &self)?;
     Ok(())
   }
+  /// restructures the `concept` tree into nested hierarchies using the
+  /// thesaurus's parent relationships, flattening any existing nesting
+  /// first so re-running this against its own output gives back the same
+  /// tree (a concept under several parents may come back in a different
+  /// position among its siblings). a cycle
+  /// is broken by refusing to re-nest a concept under an ancestor it
+  /// already appears under; anything orphaned that way is emitted flat at
+  /// the top level instead of dropped
+  fn nest_by_thesaurus_parents(self, thesaurus: &Thesaurus, diagnostics: &mut Vec<Diagnostic>) -> Self {
+    // a child nested under several parents appears once per parent after
+    // flattening; keep only the first copy
+    let mut seen_codes = HashSet::new();
+    let concepts: Vec<Concept> = Concept::flatten(self.concept).into_iter()
+      .filter(|c| seen_codes.insert(c.code.clone()))
+      .collect();
+    let by_code: HashMap<String, Concept> = concepts.iter()
+      .map(|c| (c.code.clone(), c.clone()))
+      .collect();
+    let present_parents = |code: &str| -> Vec<String> {
+      thesaurus.get(code)
+        .map(|row| row.parent.iter()
+          .filter(|p| by_code.contains_key(*p))
+          .cloned()
+          .collect())
+        .unwrap_or_default()
+    };
+    let mut children_of: HashMap<String, Vec<String>> = HashMap::new();
+    for concept in &concepts {
+      for parent in present_parents(&concept.code) {
+        children_of.entry(parent).or_default().push(concept.code.clone());
+      }
+    }
+    fn build(
+      code: &str,
+      by_code: &HashMap<String, Concept>,
+      children_of: &HashMap<String, Vec<String>>,
+      ancestors: &[String],
+      diagnostics: &mut Vec<Diagnostic>,
+    ) -> Concept {
+      let mut concept = by_code[code].clone();
+      if let Some(child_codes) = children_of.get(code) {
+        let mut nested = Vec::new();
+        for child_code in child_codes {
+          if ancestors.contains(child_code) {
+            diagnostics.push(Diagnostic::new(
+              Severity::Warn,
+              "hierarchy-cycle",
+              child_code,
+              format!("is its own ancestor via '{}'; not re-nesting", code),
+            ));
+            continue;
+          }
+          let mut next_ancestors = ancestors.to_vec();
+          next_ancestors.push(code.to_string());
+          nested.push(build(child_code, by_code, children_of, &next_ancestors, diagnostics));
+        }
+        if !nested.is_empty() {
+          concept.concept = Some(nested);
+        }
+      }
+      concept
+    }
+    let roots: Vec<Concept> = concepts.iter()
+      .filter(|c| present_parents(&c.code).is_empty())
+      .map(|c| build(&c.code, &by_code, &children_of, &[], diagnostics))
+      .collect();
+    fn collect_codes(concepts: &[Concept], seen: &mut HashSet<String>) {
+      for concept in concepts {
+        seen.insert(concept.code.clone());
+        if let Some(children) = &concept.concept {
+          collect_codes(children, seen);
+        }
+      }
+    }
+    let mut nested_codes = HashSet::new();
+    collect_codes(&roots, &mut nested_codes);
+    let orphans: Vec<Concept> = concepts.iter()
+      .filter(|c| !nested_codes.contains(&c.code))
+      .cloned()
+      .collect();
+    for orphan in &orphans {
+      diagnostics.push(Diagnostic::new(
+        Severity::Warn,
+        "hierarchy-cycle-orphan",
+        &orphan.code,
+        "excluded from the hierarchy by a parent cycle; emitted at the top level".to_string(),
+      ));
+    }
+    let mut concept = roots;
+    concept.extend(orphans);
+    CodeSystem { concept, ..self }
+  }
 }
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -315,19 +765,134 @@ fn main() {
       .expect("couldn't read thesaurus");
 
     let settings = Settings {
-      suppress_info_level: true
+      suppress_info_level: true,
+      synonym_overrides: synonym_setting_from_file(std::env::var("SYNONYMS").ok())
+        .expect("couldn't read synonym dictionary"),
+      full_thesaurus_enrichment: std::env::var("FULL_THESAURUS_ENRICHMENT").is_ok(),
+      build_hierarchy: std::env::var("BUILD_HIERARCHY").is_ok(),
+      subset_filter: std::env::var("SUBSET_FILTER").ok()
+        .map(|v| v.split(',').map(|s| s.to_string()).collect()),
+      semantic_type_filter: std::env::var("SEMANTIC_TYPE_FILTER").ok()
+        .map(|v| v.split(',').map(|s| s.to_string()).collect()),
+      diagnostics_report_path: std::env::var("DIAGNOSTICS_REPORT_PATH").ok(),
     };
     let mut system =
       CodeSystem::from_file(PATH)
       .expect("something went wrong reading old codes");
     let mut stats = Statistics::new();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
     let new_codes =
       CodeSystem::from_file(&*std::env::var("NEW_CODES")
       .unwrap_or(DEFAULT_INPUT_PATH.to_string()))
       .expect("something went wrong reading new codes");
+    system.run_migrations(true, &mut diagnostics);
     for concept in new_codes.concept {
-      system.check_and_add_concept(concept, &mut stats, &settings, &thesaurus);
+      system.check_and_add_concept(concept, &mut stats, &settings, &thesaurus, &mut diagnostics);
+    }
+    system.run_migrations(false, &mut diagnostics);
+    if settings.build_hierarchy {
+      system = system.nest_by_thesaurus_parents(&thesaurus, &mut diagnostics);
     }
+    system.bump_schema_version();
     system.to_file(OUT_PATH).expect("couldn't write to disk");
+    report_diagnostics(&diagnostics, &settings).expect("couldn't write diagnostics report");
     println!("{}", stats);
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn concept(code: &str) -> Concept {
+    Concept { code: code.to_string(), display: code.to_string(), designation: None, definition: None, concept: None }
+  }
+
+  fn thesaurus_row(code: &str, parent: &str) -> ThesaurusRow {
+    ThesaurusRow {
+      code: code.to_string(),
+      iri: String::new(),
+      parent: if parent.is_empty() { vec![] } else { vec![parent.to_string()] },
+      synonyms: vec![code.to_string()],
+      definition: String::new(),
+      display_name: None,
+      concept_status: None,
+      semantic_type: String::new(),
+      concept_in_subset: vec![],
+    }
+  }
+
+  fn test_system(concept: Vec<Concept>) -> CodeSystem {
+    CodeSystem {
+      id: "x".to_string(),
+      resource_type: "CodeSystem".to_string(),
+      url: "http://x".to_string(),
+      name: "x".to_string(),
+      title: "x".to_string(),
+      status: "active".to_string(),
+      experimental: false,
+      date: "2020".to_string(),
+      publisher: "x".to_string(),
+      description: "x".to_string(),
+      copyright: "x".to_string(),
+      case_sensitive: true,
+      content: "fragment".to_string(),
+      version: None,
+      concept,
+    }
+  }
+
+  #[test]
+  fn nests_children_under_their_thesaurus_parent() {
+    let thesaurus: Thesaurus = [thesaurus_row("C1", ""), thesaurus_row("C2", "C1")]
+      .into_iter().map(|r| (r.code.clone(), r)).collect();
+    let system = test_system(vec![concept("C1"), concept("C2")]);
+    let mut diagnostics = Vec::new();
+    let nested = system.nest_by_thesaurus_parents(&thesaurus, &mut diagnostics);
+    assert_eq!(nested.concept.len(), 1);
+    assert_eq!(nested.concept[0].code, "C1");
+    assert_eq!(nested.concept[0].concept.as_ref().unwrap()[0].code, "C2");
+  }
+
+  #[test]
+  fn re_nesting_already_nested_output_is_a_no_op() {
+    let thesaurus: Thesaurus = [thesaurus_row("C1", ""), thesaurus_row("C2", "C1")]
+      .into_iter().map(|r| (r.code.clone(), r)).collect();
+    let mut parent = concept("C1");
+    parent.concept = Some(vec![concept("C2")]);
+    let system = test_system(vec![parent]);
+    let mut diagnostics = Vec::new();
+    let nested = system.nest_by_thesaurus_parents(&thesaurus, &mut diagnostics);
+    assert_eq!(nested.concept.len(), 1);
+    assert_eq!(nested.concept[0].concept.as_ref().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn get_mutable_concept_by_code_finds_nested_children() {
+    let mut parent = concept("C1");
+    parent.concept = Some(vec![concept("C2")]);
+    let mut system = test_system(vec![parent]);
+    assert!(system.get_mutable_concept_by_code(&"C2".to_string()).is_some());
+  }
+
+  #[test]
+  fn reset_clears_designation_to_none_once_emptied() {
+    let mut c = concept("C1");
+    c.add_designation(Designation::curator_synonym("Extra".to_string()));
+    c.apply_synonym_overrides(&Setting::Reset);
+    assert!(c.designation.is_none());
+  }
+
+  #[test]
+  fn set_replaces_previously_curator_added_synonyms() {
+    let mut c = concept("C1");
+    c.apply_synonym_overrides(&Setting::Set(HashMap::from([
+      ("C1".to_string(), vec!["First".to_string()]),
+    ])));
+    assert_eq!(c.designation.as_ref().unwrap().len(), 1);
+    c.apply_synonym_overrides(&Setting::Set(HashMap::from([
+      ("C1".to_string(), vec!["Second".to_string()]),
+    ])));
+    let values: Vec<_> = c.designation.as_ref().unwrap().iter().map(|d| d.value.as_str()).collect();
+    assert_eq!(values, vec!["Second"]);
+  }
+}